@@ -1,5 +1,6 @@
 use std::{
     env,
+    sync::{mpsc as std_mpsc, Arc, Mutex},
     time::{SystemTime, UNIX_EPOCH},
 };
 
@@ -7,27 +8,190 @@ use cosmic_text::{FontSystem, SwashCache};
 use futures::StreamExt;
 use rand::{distributions, thread_rng, Rng};
 use sqlx::{query, SqlitePool};
-use syntect::{highlighting::ThemeSet, parsing::SyntaxSet};
+use syntect::{
+    highlighting::ThemeSet,
+    parsing::{ParseState, ScopeStack, SyntaxReference, SyntaxSet},
+};
 use telegram_bot::*;
+use tokio::sync::oneshot;
 
 mod render;
 
 struct State {
     pub api: Api,
     pub pool: SqlitePool,
-    pub font_system: FontSystem,
-    pub swash_cache: SwashCache,
     pub syntax_set: SyntaxSet,
     pub theme_set: ThemeSet,
     pub config: Config,
+    pub render_tx: std_mpsc::Sender<WorkerJob>,
 }
 
 struct Config {
     null_chat_id: i64,
 }
 
+/// Work submitted to the render worker pool. Both variants are CPU-bound
+/// (rendering, and the content-sniffing heuristic in `detect_syntax`) and so
+/// must not run inline on the async reactor thread.
+enum WorkerJob {
+    Render(RenderJob),
+    Detect(DetectJob),
+}
+
+/// One unit of rendering work handed off to the render worker pool.
+struct RenderJob {
+    code: String,
+    /// `SyntaxReference::name` of the syntax already resolved by the caller
+    /// (by extension, first-line sniffing, or the content heuristic).
+    syntax_name: String,
+    theme_name: String,
+    max_columns: Option<usize>,
+    show_line_numbers: bool,
+    respond_to: oneshot::Sender<render::RenderedImage>,
+}
+
+/// A request to guess a snippet's syntax from its content (see
+/// `detect_syntax`), handed off to the render worker pool since it parses
+/// the whole snippet up to [`HEURISTIC_CANDIDATE_EXTENSIONS`].len() times.
+struct DetectJob {
+    code: String,
+    /// The detected syntax's `SyntaxReference::name`, or `None` if nothing matched.
+    respond_to: oneshot::Sender<Option<String>>,
+}
+
+/// Candidate encodings tried for every render; whichever compresses smallest
+/// is uploaded, to avoid Telegram's aggressive recompression of oversized PNGs.
+const OUTPUT_FORMAT_CANDIDATES: &[render::OutputFormat] = &[
+    render::OutputFormat::Png,
+    render::OutputFormat::Jpeg { quality: 85 },
+    render::OutputFormat::WebP,
+];
+
+/// Extensions tried by [`detect_syntax`]'s content heuristic when no extension
+/// was given and first-line sniffing didn't match anything either.
+const HEURISTIC_CANDIDATE_EXTENSIONS: &[&str] = &["py", "js", "rs", "go", "c", "java", "rb", "sh"];
+
+/// Guess the syntax for an extension-less snippet: first try matching the
+/// first non-empty line against syntect's per-syntax `first_line_match`
+/// patterns (catches shebangs like `#!/usr/bin/env python` and `<?php`),
+/// then fall back to highlighting the snippet against a small candidate set
+/// and picking whichever produces the fewest shallow/unscoped tokens.
+fn detect_syntax<'a>(ps: &'a SyntaxSet, code: &str) -> Option<&'a SyntaxReference> {
+    let first_line = code.lines().find(|line| !line.trim().is_empty())?;
+    if let Some(syntax) = ps.find_syntax_by_first_line(first_line) {
+        if syntax.name != ps.find_syntax_plain_text().name {
+            return Some(syntax);
+        }
+    }
+
+    HEURISTIC_CANDIDATE_EXTENSIONS
+        .iter()
+        .filter_map(|ext| ps.find_syntax_by_extension(ext))
+        .min_by_key(|syntax| score_unscoped_tokens(ps, syntax, code))
+}
+
+/// Parse `code` with `syntax` and count how many non-whitespace stretches of
+/// text are left with at most the syntax's own base scope (e.g. bare
+/// `source.rust`) rather than a more specific scope like `keyword.control.rust`
+/// -- a wrong-guess syntax tends to leave most of the snippet with nothing but
+/// the outermost scope applied.
+fn score_unscoped_tokens(ps: &SyntaxSet, syntax: &SyntaxReference, code: &str) -> usize {
+    let mut state = ParseState::new(syntax);
+    let mut stack = ScopeStack::new();
+    let mut unscoped = 0;
+    for line in syntect::util::LinesWithEndings::from(code) {
+        let Ok(ops) = state.parse_line(line, ps) else {
+            continue;
+        };
+        let mut pos = 0;
+        let ends = ops.iter().map(|(i, op)| (*i, Some(op)));
+        for (end, op) in ends.chain(std::iter::once((line.len(), None))) {
+            if stack.len() <= 1 && !line[pos..end].trim().is_empty() {
+                unscoped += 1;
+            }
+            pos = end;
+            if let Some(op) = op {
+                let _ = stack.apply(op);
+            }
+        }
+    }
+    unscoped
+}
+
 const UNUSED_RESULT_ID: &str = "unused-result-id";
 
+/// Default soft-wrap column width for rendered snippets, so a single long
+/// line doesn't produce an image thousands of pixels wide.
+const DEFAULT_MAX_COLUMNS: usize = 120;
+
+/// How long a cached `rendered_image.file_id` is trusted before we re-render
+/// and re-upload rather than risk answering with a stale Telegram file_id.
+const RENDER_CACHE_MAX_AGE_SECS: i64 = 60 * 60 * 24 * 30; // 30 days
+
+/// Number of OS threads dedicated to CPU-bound rendering. Each owns its own
+/// `FontSystem`/`SwashCache`/`SyntaxSet`/`ThemeSet`, since rendering needs
+/// `&mut` access to the font state and shouldn't stall the async update loop.
+const NUM_RENDER_WORKERS: usize = 4;
+
+/// Spawn the render worker pool and return the channel used to submit jobs to it.
+/// Workers pull jobs off a shared receiver until the sender side is dropped.
+fn spawn_render_workers(count: usize) -> std_mpsc::Sender<WorkerJob> {
+    let (tx, rx) = std_mpsc::channel::<WorkerJob>();
+    let rx = Arc::new(Mutex::new(rx));
+
+    for _ in 0..count {
+        let rx = Arc::clone(&rx);
+        std::thread::spawn(move || {
+            let mut font_system = FontSystem::new();
+            let mut swash_cache = SwashCache::new();
+            let syntax_set = SyntaxSet::load_defaults_newlines();
+            let theme_set = ThemeSet::load_defaults();
+
+            loop {
+                let job = {
+                    let rx = rx.lock().unwrap();
+                    rx.recv()
+                };
+                let Ok(job) = job else {
+                    break; // All senders dropped; shut this worker down.
+                };
+
+                match job {
+                    WorkerJob::Render(job) => {
+                        let syntax = syntax_set
+                            .find_syntax_by_name(&job.syntax_name)
+                            .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+
+                        let rendered_image = render::draw_code(
+                            &mut font_system,
+                            &mut swash_cache,
+                            &syntax_set,
+                            &theme_set,
+                            &job.code,
+                            syntax,
+                            &job.theme_name,
+                            job.max_columns,
+                            job.show_line_numbers,
+                            OUTPUT_FORMAT_CANDIDATES,
+                        );
+
+                        // The receiving end may have given up (e.g. the
+                        // update's task was cancelled); dropping the result is fine.
+                        let _ = job.respond_to.send(rendered_image);
+                    }
+                    WorkerJob::Detect(job) => {
+                        let name =
+                            detect_syntax(&syntax_set, &job.code).map(|syntax| syntax.name.clone());
+                        let _ = job.respond_to.send(name);
+                    }
+                }
+            }
+        });
+    }
+
+    tx
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     #[allow(unused_must_use)]
@@ -40,37 +204,39 @@ async fn main() -> anyhow::Result<()> {
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
     let api = Api::new(token);
     let pool = sqlx::SqlitePool::connect(&database_url).await?;
-    let font_system = FontSystem::new();
-    let swash_cache = SwashCache::new();
     let syntax_set = SyntaxSet::load_defaults_newlines();
     let theme_set = ThemeSet::load_defaults();
     let config = Config {
         null_chat_id: -992674722,
     }; // TODO: accept this from outside
-    let mut state = State {
+    let render_tx = spawn_render_workers(NUM_RENDER_WORKERS);
+    let state = Arc::new(State {
         api,
         pool,
-        font_system,
-        swash_cache,
         config,
         syntax_set,
         theme_set,
-    };
+        render_tx,
+    });
 
     sqlx::migrate!().run(&state.pool).await?;
 
-    // Fetch new updates via long poll method
+    // Fetch new updates via long poll method. Each update is dispatched to its own task so a
+    // slow render or a slow upload for one user doesn't stall everyone else's inline query.
     let mut stream = state.api.stream();
     while let Some(update) = stream.next().await {
         let update = update?;
-        if let Err(e) = process_update(update, &mut state).await {
-            log::error!("Error while processing update: {e}");
-        }
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = process_update(update, &state).await {
+                log::error!("Error while processing update: {e}");
+            }
+        });
     }
     Ok(())
 }
 
-async fn process_update(update: Update, state: &mut State) -> anyhow::Result<()> {
+async fn process_update(update: Update, state: &State) -> anyhow::Result<()> {
     // If the received update contains a new message...
     match update.kind {
         UpdateKind::Message(message) => {
@@ -127,11 +293,9 @@ async fn process_update(update: Update, state: &mut State) -> anyhow::Result<()>
 /// Process an inline query: save the code snippet as an ephemeral gist,
 /// then render it as an image
 /// and submit this as an inline query result.
-async fn process_inline_query(state: &mut State, inline_query: InlineQuery) -> anyhow::Result<()> {
+async fn process_inline_query(state: &State, inline_query: InlineQuery) -> anyhow::Result<()> {
     let api = &state.api;
     let pool = &state.pool;
-    let font_system = &mut state.font_system;
-    let swash_cache = &mut state.swash_cache;
     let ps = &state.syntax_set;
     let ts = &state.theme_set;
     let config = &state.config;
@@ -139,12 +303,39 @@ async fn process_inline_query(state: &mut State, inline_query: InlineQuery) -> a
     let code = inline_query.query.clone();
     let mut real_code = None;
     let mut code_ext = None;
-    // If there is a single word in front of the first colon, that's considered the file extension
+    let mut code_theme = None;
+    let mut show_line_numbers = false;
+    // If there is a single word in front of the first colon, that's considered the file extension,
+    // optionally followed by `@theme_name` to pick a non-default highlighting theme and/or a
+    // trailing `#` to turn on the line-number gutter, e.g. `rust@monokai#: fn main() {}`.
     if code.find(":").is_some() {
         let first = code.split(":").nth(0).unwrap();
-        if first.find(" ").is_none() {
-            code_ext = Some(first.clone());
+        let first = match first.strip_suffix('#') {
+            Some(stripped) => {
+                show_line_numbers = true;
+                stripped
+            }
+            None => first,
+        };
+        let (ext_part, theme_part) = match first.split_once('@') {
+            Some((ext, theme)) => (ext, Some(theme)),
+            None => (first, None),
+        };
+        // Only the extension needs to be a single bare word; a theme name
+        // after `@` is allowed to contain spaces (e.g. the bundled
+        // "Solarized (dark)"). If the extension part has a space, this is
+        // probably not a prefix at all but plain code with a colon in it
+        // (e.g. Python's `if x: pass`), so leave it unparsed.
+        if !ext_part.contains(' ') {
+            code_ext = if ext_part.is_empty() {
+                None
+            } else {
+                Some(ext_part)
+            };
+            code_theme = theme_part.filter(|theme| !theme.is_empty());
             real_code = Some(code.split(":").skip(1).collect::<Vec<&str>>().join(":"));
+        } else {
+            show_line_numbers = false;
         }
     }
 
@@ -183,10 +374,34 @@ async fn process_inline_query(state: &mut State, inline_query: InlineQuery) -> a
         return Ok(());
     }
 
-    let syntax = if let Some(ext) = code_ext {
-        ps.find_syntax_by_extension(ext)
+    // With no explicit extension, try to guess the language from the snippet's content
+    // rather than always falling back to plain text. Detection re-parses the whole
+    // snippet against several candidate syntaxes, so hand it off to the render worker
+    // pool instead of running it inline on this task's async reactor thread.
+    let (syntax, detected_language) = if let Some(ext) = code_ext {
+        (ps.find_syntax_by_extension(ext), None)
     } else {
-        Some(ps.find_syntax_plain_text())
+        let (respond_to, response) = oneshot::channel();
+        state
+            .render_tx
+            .send(WorkerJob::Detect(DetectJob {
+                code: code.clone(),
+                respond_to,
+            }))
+            .map_err(|_| anyhow::anyhow!("render worker pool has shut down"))?;
+        match response.await? {
+            Some(name) => (ps.find_syntax_by_name(&name), Some(name)),
+            None => (None, None),
+        }
+    };
+    let resolved_syntax = syntax.unwrap_or_else(|| ps.find_syntax_plain_text());
+
+    // An explicit `@theme` that isn't in the loaded ThemeSet falls back to the
+    // default theme, same as an unrecognized extension falls back to plain text.
+    let unknown_theme = matches!(code_theme, Some(name) if !ts.themes.contains_key(name));
+    let theme_name = match code_theme {
+        Some(name) if ts.themes.contains_key(name) => name,
+        _ => render::DEFAULT_THEME_NAME,
     };
 
     // Keep making IDs until an insertion succeeds, up to a maximum of 100 attempts
@@ -203,8 +418,8 @@ async fn process_inline_query(state: &mut State, inline_query: InlineQuery) -> a
         for _ in 0..8 {
             id.push(rand.sample(distributions::Alphanumeric) as char);
         }
-        let result = query!("INSERT INTO gist (id, content, sent_by, sent_at_unix_time, is_ephemeral, language) VALUES (?, ?, ?, ?, 1, ?)",
-            id, code, from, now, code_ext).execute(pool).await;
+        let result = query!("INSERT INTO gist (id, content, sent_by, sent_at_unix_time, is_ephemeral, language, theme) VALUES (?, ?, ?, ?, 1, ?, ?)",
+            id, code, from, now, code_ext, theme_name).execute(pool).await;
         if result.is_err() {
             eprintln!("Error while inserting gist: {result:?}");
             attempts += 1;
@@ -216,42 +431,89 @@ async fn process_inline_query(state: &mut State, inline_query: InlineQuery) -> a
         }
     }
 
-    // In order to attach a photo, it needs to first be uploaded to some chat, which is specified by config.null_chat_id.
-    // Set this to a chat that you control.
-    // This will yield a server file_id, which can be then used in the inline query result photo.
-
-    let png_data = render::draw_code(
-        font_system,
-        swash_cache,
-        ps,
-        ts,
-        &code,
-        syntax.unwrap_or(ps.find_syntax_plain_text()),
-    );
-
-    let photo_upload = InputFileUpload::with_data(png_data, "code.png");
-
-    let upload = api
-        .send(SendPhoto::new(
-            ChatId::from(config.null_chat_id),
-            photo_upload,
-        ))
-        .await?;
-    let file_id = if let MessageKind::Photo { data, .. } = upload.kind {
-        let first = data[0].clone();
-        let largest = data.iter().fold(first, |acc, item| {
-            if (acc.width, acc.height) < (item.width, item.height) {
-                item.clone()
-            } else {
-                acc
-            }
-        });
-        largest.file_id
+    // Content-addressed cache: identical (code, language, theme, line-number flag) tuples render
+    // to the exact same image, so look it up by hash before paying for a render + re-upload.
+    let render_hash = blake3::hash(
+        format!(
+            "{}\0{}\0{}\0{}",
+            code_ext.unwrap_or(""),
+            theme_name,
+            show_line_numbers,
+            code
+        )
+        .as_bytes(),
+    )
+    .to_hex()
+    .to_string();
+
+    let cached = query!(
+        "SELECT file_id, created_at FROM rendered_image WHERE hash = ?",
+        render_hash
+    )
+    .fetch_optional(pool)
+    .await?
+    .filter(|row| now - row.created_at < RENDER_CACHE_MAX_AGE_SECS);
+
+    let file_id = if let Some(cached) = cached {
+        cached.file_id
     } else {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            format!("Uploaded image with photo but it wasn't a photo message?!"),
-        ))?;
+        // In order to attach a photo, it needs to first be uploaded to some chat, which is specified by config.null_chat_id.
+        // Set this to a chat that you control.
+        // This will yield a server file_id, which can be then used in the inline query result photo.
+
+        // Hand the CPU-bound rendering off to the worker pool and await the result,
+        // so this task doesn't block other updates while the image is drawn.
+        let (respond_to, response) = oneshot::channel();
+        state
+            .render_tx
+            .send(WorkerJob::Render(RenderJob {
+                code: code.clone(),
+                syntax_name: resolved_syntax.name.clone(),
+                theme_name: theme_name.to_string(),
+                max_columns: Some(DEFAULT_MAX_COLUMNS),
+                show_line_numbers,
+                respond_to,
+            }))
+            .map_err(|_| anyhow::anyhow!("render worker pool has shut down"))?;
+        let rendered_image = response.await?;
+
+        let photo_upload =
+            InputFileUpload::with_data(rendered_image.data, rendered_image.format.file_name());
+
+        let upload = api
+            .send(SendPhoto::new(
+                ChatId::from(config.null_chat_id),
+                photo_upload,
+            ))
+            .await?;
+        let file_id = if let MessageKind::Photo { data, .. } = upload.kind {
+            let first = data[0].clone();
+            let largest = data.iter().fold(first, |acc, item| {
+                if (acc.width, acc.height) < (item.width, item.height) {
+                    item.clone()
+                } else {
+                    acc
+                }
+            });
+            largest.file_id
+        } else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Uploaded image with photo but it wasn't a photo message?!"),
+            ))?;
+        };
+
+        query!(
+            "INSERT INTO rendered_image (hash, file_id, created_at) VALUES (?, ?, ?)
+             ON CONFLICT(hash) DO UPDATE SET file_id = excluded.file_id, created_at = excluded.created_at",
+            render_hash,
+            file_id,
+            now
+        )
+        .execute(pool)
+        .await?;
+
+        file_id
     };
 
     let language = if code_ext.is_some() {
@@ -264,12 +526,25 @@ async fn process_inline_query(state: &mut State, inline_query: InlineQuery) -> a
                 ""
             }
         )
+    } else if let Some(detected) = &detected_language {
+        format!(r"Language \(auto\-detected\): `{}`", detected)
     } else {
+        // For non-blank code, `detect_syntax` always resolves to some syntax (at worst one
+        // of `HEURISTIC_CANDIDATE_EXTENSIONS`) with the bundled
+        // `SyntaxSet::load_defaults_newlines()`, so this is
+        // unreachable today; kept as a defensive fallback in case that candidate
+        // list or the loaded syntax set ever changes and detection comes up empty.
         format!(
             r"Language unknown \(try `py:print\('Hello World'\)` and `cpp:int main\(int argc, char **argv\);`\)"
         )
     };
 
+    let theme_note = if unknown_theme {
+        r" \(unknown theme, using default\)"
+    } else {
+        ""
+    };
+
     api.send(
         inline_query.answer(vec![InlineQueryResult::InlineQueryResultCachedPhoto(
             InlineQueryResultCachedPhoto {
@@ -277,7 +552,10 @@ async fn process_inline_query(state: &mut State, inline_query: InlineQuery) -> a
                 photo_file_id: file_id,
                 title: None,
                 description: None,
-                caption: Some(format!("Code snippet ID: `{id}` {}", language)),
+                caption: Some(format!(
+                    "Code snippet ID: `{id}` {}{}",
+                    language, theme_note
+                )),
                 parse_mode: Some(ParseMode::MarkdownV2),
                 reply_markup: None,
                 input_message_content: None,
@@ -287,3 +565,46 @@ async fn process_inline_query(state: &mut State, inline_query: InlineQuery) -> a
     .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn syntax_set() -> SyntaxSet {
+        SyntaxSet::load_defaults_newlines()
+    }
+
+    #[test]
+    fn detect_syntax_prefers_shebang_over_heuristic() {
+        let ps = syntax_set();
+        let code = "#!/usr/bin/env python3\nprint('hi')\n";
+        let detected = detect_syntax(&ps, code).expect("should detect a syntax");
+        let expected = ps.find_syntax_by_extension("py").unwrap();
+        assert_eq!(detected.name, expected.name);
+    }
+
+    #[test]
+    fn detect_syntax_falls_back_to_heuristic_without_a_shebang() {
+        let ps = syntax_set();
+        let code = "fn main() {\n    println!(\"hi\");\n}\n";
+        let detected = detect_syntax(&ps, code).expect("should detect a syntax");
+        let expected = ps.find_syntax_by_extension("rs").unwrap();
+        assert_eq!(detected.name, expected.name);
+    }
+
+    #[test]
+    fn detect_syntax_returns_none_for_blank_code() {
+        let ps = syntax_set();
+        assert!(detect_syntax(&ps, "   \n   \n").is_none());
+    }
+
+    #[test]
+    fn score_unscoped_tokens_ties_do_not_panic() {
+        // A single bare word is left as one unscoped token by several
+        // candidate syntaxes, so they tie on score; picking among ties
+        // must not panic and must still return a syntax.
+        let ps = syntax_set();
+        let code = "hello";
+        assert!(detect_syntax(&ps, code).is_some());
+    }
+}